@@ -1,43 +1,298 @@
-use std::{cell::RefCell, rc::Rc};
-
 use crate::tsp;
-use std::collections::VecDeque;
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, VecDeque};
+
+type LabelHandle = u32;
+
+/// Search strategy for `solve`.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum SolveMode {
+    /// FIFO label-correcting (SPFA-style): the original behavior. Labels can
+    /// be re-extended as better paths to a vertex are discovered.
+    LabelCorrecting,
+    /// Monotone label-setting: labels are popped from a `BinaryHeap` in order
+    /// of `cost + h(at)`, an admissible lower bound on the completed route
+    /// cost. Because the key never overestimates, the first non-trivial
+    /// depot label that survives dominance on pop is optimal, so the search
+    /// returns there and every label still in the heap is discarded without
+    /// being extended. If `h` can't be computed
+    /// admissibly (a negative-cost cycle reachable in `aux`), this falls
+    /// back to draining the whole heap and taking the best depot label
+    /// found, same as `LabelCorrecting` would.
+    LabelSetting,
+}
+
+/// A pluggable resource (capacity/time-window/etc.) keyed on `(from, to, r)`.
+/// `extend` accumulates `q[r] += consume(r, from, to)` and feasibility
+/// rejects once `q[r]` exceeds `capacity(r)`, so any resource whose
+/// consumption is additive along a path (and therefore dominance-safe via
+/// plain component-wise comparison of `q`) can be plugged in here.
+///
+/// This only covers capacities (upper bounds): `dominates` compares `q`
+/// component-wise assuming smaller-is-better, which a lower window bound
+/// (e.g. a time window's earliest-arrival side) doesn't satisfy --
+/// `consumed < lowerbound` isn't monotonic the same way, so enforcing one
+/// needs a dominance-safe check this trait doesn't offer. An earlier pass
+/// added a `lowerbound` hook and enforced it unsoundly; it was reverted
+/// rather than ship a broken check, so a lower-bound resource remains
+/// unimplemented here.
+pub trait ResourceExtension {
+    /// how much of resource `r` does traversing the arc `from -> to` consume?
+    fn consume(&self, r: usize, from: usize, to: usize) -> usize;
+    /// the capacity of resource `r`.
+    fn capacity(&self, r: usize) -> usize;
+}
+
+/// The original resource model: resource `r` is consumed by an arc iff bit
+/// `r` is set in the destination vertex's index, and every resource shares
+/// the same `resourcecapacity`. `solve` has no implicit default resource
+/// model; callers pass this struct to get the pre-existing behavior unchanged.
+pub struct BitmaskResources {
+    pub resourcecapacity: usize,
+}
+
+impl ResourceExtension for BitmaskResources {
+    fn consume(&self, r: usize, _from: usize, to: usize) -> usize {
+        if (to & (1 << r)) > 0 {
+            1
+        } else {
+            0
+        }
+    }
+
+    fn capacity(&self, _r: usize) -> usize {
+        self.resourcecapacity
+    }
+}
+
+// Admissible lower bound h(v) on the cheapest remaining `aux` cost from `v`
+// back to the depot, resources and length ignored. Computed by relaxing
+// every directed edge until fixpoint (Bellman-Ford), since `aux` costs can
+// be negative (e.g. reduced costs in a pricing subproblem) and so rule out
+// a plain Dijkstra pass.
+//
+// `h` ignores elementarity/ng-memory entirely, so a negative-cost cycle
+// reachable in `aux` (one the real search could never actually traverse
+// twice) can still stop this relaxation from ever reaching a fixpoint.
+// The second element of the return value reports whether it did: `false`
+// means `h` is not a valid lower bound and callers must not trust it for
+// anything that depends on admissibility (e.g. `solve_labelsetting`'s
+// first-pop-is-optimal early return).
+fn lowerbounds(d: &tsp::TSPData) -> (Vec<f64>, bool) {
+    let mut h: Vec<f64> = vec![f64::INFINITY; d.n];
+    h[0] = 0.0;
+    let mut changed = true;
+    for _ in 0..d.n {
+        changed = false;
+        for v in 0..d.n {
+            for w in 0..d.n {
+                if v == w || !h[w].is_finite() {
+                    continue;
+                }
+                let cand = d.aux(v, w) + h[w];
+                if cand < h[v] {
+                    h[v] = cand;
+                    changed = true;
+                }
+            }
+        }
+        if !changed {
+            break;
+        }
+    }
+    (h, !changed)
+}
+
+// Precomputed ng-route neighborhoods: `neighbors[v][w]` is set iff `w` is
+// among the `k` vertices nearest to `v` by `d`. Used to decremently relax
+// the elementarity requirement: a label only needs to remember a vertex `x`
+// in its ng-memory while the vertex it is currently at is still one of `x`'s
+// `k` nearest neighbors.
+fn ngneighbors(d: &tsp::TSPData, k: usize) -> Vec<Vec<bool>> {
+    let n = d.n;
+    let mut neighbors = vec![vec![false; n]; n];
+    for v in 0..n {
+        let mut others: Vec<usize> = (0..n).filter(|&w| w != v).collect();
+        others.sort_by_key(|&w| d.d(v, w));
+        for &w in others.iter().take(k) {
+            neighbors[v][w] = true;
+        }
+    }
+    neighbors
+}
+
+// An open label waiting in the label-setting priority queue, ordered so
+// `BinaryHeap` (a max-heap) pops the smallest `key` first.
+struct HeapEntry {
+    key: f64,
+    handle: LabelHandle,
+}
+
+impl PartialEq for HeapEntry {
+    fn eq(&self, other: &Self) -> bool {
+        self.key == other.key
+    }
+}
+
+impl Eq for HeapEntry {}
+
+impl PartialOrd for HeapEntry {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for HeapEntry {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.key.partial_cmp(&self.key).unwrap_or(Ordering::Equal)
+    }
+}
+
+// A slot in the label arena: either a live label, or a free slot threaded
+// into the arena's free list (the tag is the enum discriminant itself).
+enum Slot {
+    Occupied(Label),
+    Free { next: Option<LabelHandle> },
+}
+
+// Slab-style storage for `Label`s. Labels are addressed by a stable `u32`
+// handle rather than `Rc<RefCell<_>>`, and slots vacated by dominated
+// labels are threaded onto `free_head` so `alloc` can reuse them instead of
+// growing the backing `Vec`.
+struct LabelArena {
+    slots: Vec<Slot>,
+    free_head: Option<LabelHandle>,
+}
+
+impl LabelArena {
+    fn new() -> LabelArena {
+        LabelArena {
+            slots: Vec::new(),
+            free_head: None,
+        }
+    }
+
+    fn alloc(&mut self, label: Label) -> LabelHandle {
+        if let Some(h) = self.free_head {
+            let next = match &self.slots[h as usize] {
+                Slot::Free { next } => *next,
+                Slot::Occupied(_) => unreachable!("free list points at an occupied slot"),
+            };
+            self.free_head = next;
+            self.slots[h as usize] = Slot::Occupied(label);
+            h
+        } else {
+            let h = self.slots.len() as LabelHandle;
+            self.slots.push(Slot::Occupied(label));
+            h
+        }
+    }
+
+    // Release one strong reference to `h`. A label is kept alive by two
+    // kinds of reference: being the current occupant of a `labels[v]` or
+    // heap slot, and being the predecessor of another still-alive label
+    // (every `extend` bumps its `from` label's count by one). Only once
+    // the count drops to zero is the slot actually reclaimed -- and at
+    // that point we also unlink `h` from its own predecessor's
+    // `successors` list and release the predecessor's reference in turn,
+    // so a reclaimed (and later reused) handle can never be aliased into
+    // by a stale entry left over in some other label's `successors`, and
+    // a label with live descendants is never reclaimed out from under
+    // their `predecessor` chain.
+    //
+    // Calling this doesn't by itself guarantee a label marked `ignore`
+    // (dominated via an ancestor's marksuccessors cascade) gets reclaimed
+    // promptly: `marksuccessors` only flips the flag on descendants, it
+    // doesn't walk the arena freeing them. `run_labelcorrecting`'s main
+    // loop is what actually calls `free` on those -- it happens the next
+    // time that label's vertex is dequeued and its (now-stale) entry is
+    // found sitting in `labels[n]`, not the instant it's dominated.
+    fn free(&mut self, h: LabelHandle) {
+        let mut current = h;
+        loop {
+            let refs = match &mut self.slots[current as usize] {
+                Slot::Occupied(label) => {
+                    label.refs -= 1;
+                    label.refs
+                }
+                Slot::Free { .. } => unreachable!("double free of a label slot"),
+            };
+            if refs > 0 {
+                return;
+            }
+            let predecessor = match &self.slots[current as usize] {
+                Slot::Occupied(label) => label.predecessor,
+                Slot::Free { .. } => unreachable!(),
+            };
+            if let Some(p) = predecessor {
+                if let Slot::Occupied(plabel) = &mut self.slots[p as usize] {
+                    plabel.successors.retain(|&s| s != current);
+                }
+            }
+            self.slots[current as usize] = Slot::Free {
+                next: self.free_head,
+            };
+            self.free_head = Some(current);
+            match predecessor {
+                Some(p) => current = p,
+                None => return,
+            }
+        }
+    }
 
-type LabelRef = Rc<RefCell<Label>>;
+    fn get(&self, h: LabelHandle) -> &Label {
+        match &self.slots[h as usize] {
+            Slot::Occupied(label) => label,
+            Slot::Free { .. } => unreachable!("handle refers to a freed slot"),
+        }
+    }
+
+    fn get_mut(&mut self, h: LabelHandle) -> &mut Label {
+        match &mut self.slots[h as usize] {
+            Slot::Occupied(label) => label,
+            Slot::Free { .. } => unreachable!("handle refers to a freed slot"),
+        }
+    }
+}
 
 struct Label {
     at: usize,
-    visited: Vec<bool>,
+    // ng-route memory set M: a bitset of vertices this label must not
+    // revisit yet (see `ngneighbors`), not the full visited set
+    memory: Vec<bool>,
     ignore: bool,
-    predecessor: Option<LabelRef>,
+    predecessor: Option<LabelHandle>,
     cost: f64,
     length: i32,
     q: Vec<usize>,
-    successors: Vec<LabelRef>,
+    successors: Vec<LabelHandle>,
+    // strong-reference count backing `LabelArena::free`; see its doc comment
+    refs: u32,
 }
 
 impl Label {
-    fn empty(d: &tsp::TSPData, nresources: usize) -> LabelRef {
-        let visited: Vec<bool> = vec![false; d.n];
+    fn empty(d: &tsp::TSPData, nresources: usize) -> Label {
+        let memory: Vec<bool> = vec![false; d.n];
         let q: Vec<usize> = vec![0; nresources];
-        Rc::new(RefCell::new(Label {
+        Label {
             at: 0,
-            visited: visited,
+            memory: memory,
             ignore: false,
             predecessor: None,
             cost: 0.0,
             length: 0,
             q: q,
             successors: Vec::new(),
-        }))
+            refs: 1,
+        }
     }
 
     fn dominates(&self, other: &Label) -> bool {
         if self.cost > other.cost || self.length > other.length {
             return false;
         }
-        for (v1, v2) in self.visited.iter().zip(other.visited.iter()) {
-            if v1 > v2 {
+        for (m1, m2) in self.memory.iter().zip(other.memory.iter()) {
+            if m1 > m2 {
                 return false;
             }
         }
@@ -49,123 +304,564 @@ impl Label {
         return true;
     }
 
-    // extend label to given vertex
-    fn extend(d: &tsp::TSPData, from: &LabelRef, vertex: usize) -> LabelRef {
-        let mut visited = from.borrow().visited.clone();
-        visited[vertex] = true;
-        let mut q = from.borrow().q.clone();
-        for i in 0..q.len() {
-            if (vertex & (1 << i)) > 0 {
-                q[i] += 1;
+    // extend label to given vertex, allocating (or reusing) a slot in `arena`.
+    // `neighbors` is the ng-route table from `ngneighbors`: the new memory
+    // keeps only the vertices `x` still held by `from` for which `vertex` is
+    // one of `x`'s `k` nearest neighbors, plus `vertex` itself.
+    fn extend(
+        d: &tsp::TSPData,
+        arena: &mut LabelArena,
+        neighbors: &[Vec<bool>],
+        resources: &dyn ResourceExtension,
+        from: LabelHandle,
+        vertex: usize,
+    ) -> LabelHandle {
+        let from_label = arena.get(from);
+        let mut memory = vec![false; from_label.memory.len()];
+        for x in 0..from_label.memory.len() {
+            if from_label.memory[x] && neighbors[x][vertex] {
+                memory[x] = true;
             }
         }
-        let cost = from.borrow().cost + d.aux(from.borrow().at, vertex);
-        let length = from.borrow().length + d.d(from.borrow().at, vertex);
-        Rc::new(RefCell::new(Label {
+        memory[vertex] = true;
+        let mut q = from_label.q.clone();
+        for r in 0..q.len() {
+            q[r] += resources.consume(r, from_label.at, vertex);
+        }
+        let cost = from_label.cost + d.aux(from_label.at, vertex);
+        let length = from_label.length + d.d(from_label.at, vertex);
+        let child = arena.alloc(Label {
             at: vertex,
-            visited: visited,
+            memory: memory,
             ignore: false,
-            predecessor: Some(from.clone()),
+            predecessor: Some(from),
             cost: cost,
             length: length,
             q: q,
             successors: Vec::new(),
-        }))
+            refs: 1,
+        });
+        // the child's `predecessor` link is itself a strong reference,
+        // keeping `from` alive for as long as the child (or any of its
+        // own descendants) is, regardless of what later happens to
+        // `from`'s own labels[v]/heap membership
+        arena.get_mut(from).refs += 1;
+        child
     }
 
-    fn addsuccessor(&mut self, successor: &LabelRef) {
-        self.successors.push(successor.clone());
+    fn addsuccessor(&mut self, successor: LabelHandle) {
+        self.successors.push(successor);
     }
 
-    fn marksuccessors(&self) {
-        for successor in &self.successors {
-            let mut successor = successor.borrow_mut();
-            successor.ignore = true;
-            successor.marksuccessors();
+    fn marksuccessors(arena: &mut LabelArena, h: LabelHandle) {
+        let successors = arena.get(h).successors.clone();
+        for successor in successors {
+            arena.get_mut(successor).ignore = true;
+            Label::marksuccessors(arena, successor);
         }
     }
 
-    fn updatedominance(labels: &mut Vec<LabelRef>, new_label: &LabelRef) -> bool {
+    // returns whether `new_label` was added to `labels`; every label in
+    // `labels` it dominates is removed and its reference released via
+    // `arena.free`
+    fn updatedominance(
+        arena: &mut LabelArena,
+        labels: &mut Vec<LabelHandle>,
+        new_label: LabelHandle,
+    ) -> bool {
         let mut i: usize = 0;
         while i < labels.len() {
-            if labels[i].borrow().dominates(&new_label.borrow()) {
+            if arena.get(labels[i]).dominates(arena.get(new_label)) {
                 return false;
             }
-            if new_label.borrow().dominates(&labels[i].borrow()) {
-                labels[i].borrow().marksuccessors();
-                let last = labels.pop();
+            if arena.get(new_label).dominates(arena.get(labels[i])) {
+                Label::marksuccessors(arena, labels[i]);
+                let dominated = labels[i];
+                let last = labels.pop().unwrap();
                 if i < labels.len() {
-                    labels[i] = last.unwrap();
+                    labels[i] = last;
+                } else {
+                    // dominated was the last entry; nothing to move into place
                 }
+                arena.free(dominated);
             } else {
                 i += 1;
             }
         }
         // at this point the new label is not dominated so we add it
-        labels.push(new_label.clone());
+        labels.push(new_label);
         return true;
     }
 }
 
-pub fn solve(d: &tsp::TSPData, nresources: usize, resourcecapacity: usize, maxlen: i32) -> f64 {
+// is extending `label` (currently at `label.at`) to `succ` length- and
+// resource-feasible? shared between the label-correcting and label-setting
+// loops of `solve`.
+fn feasible(
+    d: &tsp::TSPData,
+    label: &Label,
+    succ: usize,
+    nresources: usize,
+    resources: &dyn ResourceExtension,
+    maxlen: i32,
+) -> bool {
+    if label.memory[succ] || succ == label.at {
+        return false;
+    }
+    if label.length + d.d(label.at, succ) + d.d(succ, 0) > maxlen {
+        return false;
+    }
+    for r in 0..nresources {
+        // only a capacity (upper bound) is enforced here: `dominates`
+        // compares `q` component-wise assuming smaller-is-better, which
+        // holds for a capacity but not for a lower window bound, so
+        // `ResourceExtension` has no lower-bound hook to plug in -- adding
+        // one would need a dominance-safe enforcement path first
+        if label.q[r] + resources.consume(r, label.at, succ) > resources.capacity(r) {
+            return false;
+        }
+    }
+    true
+}
+
+pub fn solve(
+    d: &tsp::TSPData,
+    nresources: usize,
+    resources: &dyn ResourceExtension,
+    maxlen: i32,
+    mode: SolveMode,
+    k: usize,
+) -> f64 {
+    match mode {
+        SolveMode::LabelCorrecting => solve_labelcorrecting(d, nresources, resources, maxlen, k),
+        SolveMode::LabelSetting => solve_labelsetting(d, nresources, resources, maxlen, k),
+    }
+}
+
+fn solve_labelcorrecting(
+    d: &tsp::TSPData,
+    nresources: usize,
+    resources: &dyn ResourceExtension,
+    maxlen: i32,
+    k: usize,
+) -> f64 {
+    let (arena, labels) = run_labelcorrecting(d, nresources, resources, maxlen, k);
+    let mut bestcost: f64 = arena.get(labels[0][0]).cost;
+    for i in 1..labels[0].len() {
+        let cost = arena.get(labels[0][i]).cost;
+        if cost < bestcost {
+            bestcost = cost;
+        }
+    }
+    return bestcost;
+}
+
+// A completed route from the depot back to the depot, as discovered by the
+// label-correcting DP, paired with its total reduced cost.
+pub struct Route {
+    pub cost: f64,
+    pub vertices: Vec<usize>,
+}
+
+// Reconstruct the vertex sequence of a depot-to-depot route by walking the
+// label's predecessor chain back to the initial (predecessor-less) label.
+fn reconstructroute(arena: &LabelArena, mut h: LabelHandle) -> Vec<usize> {
+    let mut vertices = Vec::new();
+    loop {
+        let label = arena.get(h);
+        vertices.push(label.at);
+        match label.predecessor {
+            Some(p) => h = p,
+            None => break,
+        }
+    }
+    vertices.reverse();
+    vertices
+}
+
+// Run the same label-correcting DP as `solve`, but instead of collapsing to
+// a single best cost, reconstruct and return every completed depot route
+// whose cost is below `threshold`, sorted by cost ascending and capped at
+// `max_routes`. Intended as the pricing subproblem solver in a branch-and-
+// price column generation loop, where `threshold` is typically `0.0`.
+pub fn solve_columns(
+    d: &tsp::TSPData,
+    nresources: usize,
+    resources: &dyn ResourceExtension,
+    maxlen: i32,
+    k: usize,
+    threshold: f64,
+    max_routes: usize,
+) -> Vec<Route> {
+    let (arena, labels) = run_labelcorrecting(d, nresources, resources, maxlen, k);
+    let mut routes: Vec<Route> = labels[0]
+        .iter()
+        .map(|&h| Route {
+            cost: arena.get(h).cost,
+            vertices: reconstructroute(&arena, h),
+        })
+        .filter(|route| route.cost < threshold)
+        .collect();
+    routes.sort_by(|a, b| a.cost.partial_cmp(&b.cost).unwrap());
+    routes.truncate(max_routes);
+    routes
+}
+
+// shared DP loop behind `solve_labelcorrecting` and `solve_columns`: runs
+// the label-correcting search to completion and hands back the arena and
+// the per-vertex settled label lists it produced.
+fn run_labelcorrecting(
+    d: &tsp::TSPData,
+    nresources: usize,
+    resources: &dyn ResourceExtension,
+    maxlen: i32,
+    k: usize,
+) -> (LabelArena, Vec<Vec<LabelHandle>>) {
+    let neighbors = ngneighbors(d, k);
+    let mut arena = LabelArena::new();
     // we will store all labels here
     let mut q: VecDeque<usize> = VecDeque::new();
     let mut in_q: Vec<bool> = vec![false; d.n];
     // initial label
-    let l0 = Label::empty(d, nresources);
+    let l0 = arena.alloc(Label::empty(d, nresources));
     q.push_back(0);
     in_q[0] = true;
     // considered labels at each node
-    let mut labels: Vec<Vec<LabelRef>> = vec![Vec::new(); d.n];
+    let mut labels: Vec<Vec<LabelHandle>> = vec![Vec::new(); d.n];
     labels[0].push(l0);
     // main DP loop
     while !q.is_empty() {
         let n = q.pop_front().unwrap();
         in_q[n] = false;
-        for i in 0..labels[n].len() {
-            let lind = labels[n][i].clone();
-            if lind.borrow().ignore == true {
+        let mut i = 0;
+        while i < labels[n].len() {
+            let lind = labels[n][i];
+            if arena.get(lind).ignore == true {
+                // either dominated via an ancestor's marksuccessors cascade,
+                // or already fully expanded on an earlier visit to this
+                // vertex: it no longer needs to stay in the settled list,
+                // so drop it and release its reference now; its slot is
+                // reclaimed once no live descendant still points back at it.
+                labels[n].swap_remove(i);
+                arena.free(lind);
                 continue;
             }
             for succ in 0..d.n {
-                if lind.borrow().visited[succ] || succ == n {
-                    continue;
-                }
-                // is the extension length-feasible?
-                if lind.borrow().length + d.d(n, succ) + d.d(succ, 0) > maxlen {
-                    continue;
-                }
-                // is it resource-feasible?
-                let mut rfeas: bool = true;
-                for r in 0..nresources {
-                    if (succ & (1 << r)) > 0 && lind.borrow().q[r] + 1 > resourcecapacity {
-                        rfeas = false;
-                        break;
-                    }
-                }
-                if !rfeas {
+                if !feasible(d, arena.get(lind), succ, nresources, resources, maxlen) {
                     continue;
                 }
                 // at this point we know the extension is feasible
-                let nl = Label::extend(d, &lind, succ);
-                let added = Label::updatedominance(&mut labels[succ], &nl);
+                let nl = Label::extend(d, &mut arena, &neighbors, resources, lind, succ);
+                let added = Label::updatedominance(&mut arena, &mut labels[succ], nl);
                 if added {
-                    lind.borrow_mut().addsuccessor(&nl);
+                    arena.get_mut(lind).addsuccessor(nl);
                     if !in_q[succ] && succ != 0 {
                         q.push_back(succ);
                         in_q[succ] = true;
                     }
+                } else {
+                    arena.free(nl);
                 }
             }
-            lind.borrow_mut().ignore = true;
+            arena.get_mut(lind).ignore = true;
+            i += 1;
         }
     }
-    let mut bestcost: f64 = labels[0][0].borrow().cost;
+    (arena, labels)
+}
+
+fn solve_labelsetting(
+    d: &tsp::TSPData,
+    nresources: usize,
+    resources: &dyn ResourceExtension,
+    maxlen: i32,
+    k: usize,
+) -> f64 {
+    let (h, h_converged) = lowerbounds(d);
+    let neighbors = ngneighbors(d, k);
+    let mut arena = LabelArena::new();
+    let mut open: BinaryHeap<HeapEntry> = BinaryHeap::new();
+    // settled, dominance-checked labels at each vertex
+    let mut labels: Vec<Vec<LabelHandle>> = vec![Vec::new(); d.n];
+    let l0 = arena.alloc(Label::empty(d, nresources));
+    open.push(HeapEntry {
+        key: h[0],
+        handle: l0,
+    });
+    while let Some(entry) = open.pop() {
+        let lind = entry.handle;
+        if arena.get(lind).ignore {
+            // already known dominated via an ancestor's marksuccessors
+            // cascade while it sat unpopped in the heap: release the
+            // heap's reference to it
+            arena.free(lind);
+            continue;
+        }
+        // lazy dominance: the label was pushed into the heap speculatively
+        // on extension, so only now, on pop, do we run the full dominance
+        // scan against the other settled labels at its vertex
+        let at = arena.get(lind).at;
+        if !Label::updatedominance(&mut arena, &mut labels[at], lind) {
+            // dominated by an already-settled label at `at`: release the
+            // heap's reference instead of leaving it allocated forever
+            arena.free(lind);
+            continue;
+        }
+        if at == 0 && arena.get(lind).predecessor.is_some() {
+            // The predecessor check excludes the trivial initial label at
+            // the depot (pushed before any extension, cost 0 by
+            // construction), which is not a completed route.
+            if h_converged {
+                // the heap pops in non-decreasing key order and h(0) == 0,
+                // so `entry.key` (== this label's cost) is a valid lower
+                // bound on every completed route still in the queue: this
+                // one is optimal.
+                return arena.get(lind).cost;
+            }
+            // `h` never reached a fixpoint (a negative-cost cycle is
+            // reachable in `aux`, ignoring the elementarity/ng-memory that
+            // would actually forbid traversing it twice), so it isn't a
+            // valid admissible bound and the first-pop-is-optimal argument
+            // above doesn't hold: some later pop could still be cheaper.
+            // `updatedominance` already settled this label into labels[0],
+            // so just keep draining the heap -- the fallback scan below
+            // will pick the true minimum once it's empty.
+            continue;
+        }
+        for succ in 0..d.n {
+            if !feasible(d, arena.get(lind), succ, nresources, resources, maxlen) {
+                continue;
+            }
+            let nl = Label::extend(d, &mut arena, &neighbors, resources, lind, succ);
+            let key = arena.get(nl).cost + h[succ];
+            arena.get_mut(lind).addsuccessor(nl);
+            open.push(HeapEntry { key, handle: nl });
+        }
+    }
+    // Either the heap emptied without ever popping a non-trivial completed
+    // route (e.g. `maxlen` or a resource capacity rules out every round
+    // trip except the trivial empty one), or `h` never converged and every
+    // non-dominated depot completion got recorded into labels[0] above instead of being
+    // trusted as an early return. Either way `labels[0]` always holds at
+    // least the initial label, so fall back to the best cost seen there,
+    // matching `solve_labelcorrecting`'s behavior on the same instance
+    // rather than assuming this case can't happen.
+    let mut bestcost = arena.get(labels[0][0]).cost;
     for i in 1..labels[0].len() {
-        let cost = labels[0][i].borrow().cost;
+        let cost = arena.get(labels[0][i]).cost;
         if cost < bestcost {
             bestcost = cost;
         }
     }
-    return bestcost;
+    bestcost
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn instance(n: usize, aux: Vec<Vec<f64>>, dist: Vec<Vec<i32>>) -> tsp::TSPData {
+        tsp::TSPData { n, aux, dist }
+    }
+
+    // Depot 0, vertices 1 and 2, n small enough that k=2 gives every label
+    // the full neighborhood (plain elementarity, no ng relaxation). The
+    // cheapest elementary depot route is 0-1-2-0 at -6; 0-2-1-0 is far
+    // worse because of the 100-cost 2->1 arc, and the single-hop routes
+    // (0-1-0 at -5, 0-2-0 at -3) and the trivial empty route (0) are also
+    // worse than -6.
+    #[test]
+    fn arena_label_correcting_finds_known_optimum() {
+        let d = instance(
+            3,
+            vec![
+                vec![0.0, -5.0, -3.0],
+                vec![0.0, 0.0, -1.0],
+                vec![0.0, 100.0, 0.0],
+            ],
+            vec![vec![0, 1, 1], vec![1, 0, 1], vec![1, 1, 0]],
+        );
+        let resources = BitmaskResources {
+            resourcecapacity: 0,
+        };
+        let cost = solve(&d, 0, &resources, 10, SolveMode::LabelCorrecting, 2);
+        assert_eq!(cost, -6.0);
+    }
+
+    // The exact scenario from the regression report: a 3-vertex graph whose
+    // cheapest depot route (0-1-2-0) costs -15. `LabelSetting` must find the
+    // same optimum as `LabelCorrecting`, not the trivial empty route's 0.0.
+    #[test]
+    fn label_setting_matches_label_correcting_on_negative_cost_instance() {
+        let d = instance(
+            3,
+            vec![
+                vec![0.0, -10.0, 0.0],
+                vec![0.0, 0.0, -5.0],
+                vec![0.0, 0.0, 0.0],
+            ],
+            vec![vec![0, 1, 1], vec![1, 0, 1], vec![1, 1, 0]],
+        );
+        let resources = BitmaskResources {
+            resourcecapacity: 0,
+        };
+        let correcting = solve(&d, 0, &resources, 10, SolveMode::LabelCorrecting, 2);
+        let setting = solve(&d, 0, &resources, 10, SolveMode::LabelSetting, 2);
+        assert_eq!(correcting, -15.0);
+        assert_eq!(setting, -15.0);
+    }
+
+    // `aux` has a reachable negative-cost cycle (2->3->2 costs -5+4=-1) that
+    // full elementarity (k=4, every vertex remembered) would never actually
+    // let the search traverse twice, but `lowerbounds` ignores elementarity
+    // entirely and so never reaches a fixpoint computing h(2)/h(3). Before
+    // the fix, `solve_labelsetting` trusted the first depot pop regardless,
+    // returning a worse-than-optimal cost; it must instead fall back to
+    // draining the heap and match `LabelCorrecting`'s true optimum, the
+    // elementary route 0-1-2-3-0 at -2 + -1 + -5 + -3 = -11.
+    #[test]
+    fn label_setting_matches_label_correcting_with_unreachable_negative_cycle() {
+        let d = instance(
+            4,
+            vec![
+                vec![0.0, -2.0, 0.0, 100.0],
+                vec![100.0, 0.0, -1.0, 100.0],
+                vec![100.0, 3.0, 0.0, -5.0],
+                vec![-3.0, 100.0, 4.0, 0.0],
+            ],
+            vec![
+                vec![0, 1, 1, 1],
+                vec![1, 0, 1, 1],
+                vec![1, 1, 0, 1],
+                vec![1, 1, 1, 0],
+            ],
+        );
+        let resources = BitmaskResources {
+            resourcecapacity: 0,
+        };
+        let correcting = solve(&d, 0, &resources, 10, SolveMode::LabelCorrecting, 4);
+        let setting = solve(&d, 0, &resources, 10, SolveMode::LabelSetting, 4);
+        assert_eq!(correcting, -11.0);
+        assert_eq!(setting, -11.0);
+    }
+
+    // When `maxlen` rules out every non-trivial round trip, the only
+    // completed "route" is the trivial empty one at the depot (cost 0),
+    // which has no predecessor. `LabelSetting` must fall back to that cost
+    // instead of running out of heap entries and panicking.
+    #[test]
+    fn label_setting_falls_back_to_trivial_route_when_maxlen_forbids_all_others() {
+        let d = instance(2, vec![vec![0.0, 0.0], vec![0.0, 0.0]], vec![vec![0, 5], vec![5, 0]]);
+        let resources = BitmaskResources {
+            resourcecapacity: 0,
+        };
+        let cost = solve(&d, 0, &resources, 1, SolveMode::LabelSetting, 1);
+        assert_eq!(cost, 0.0);
+    }
+
+    // With full neighbor memory (k=2) vertices 1 and 2 can't be revisited,
+    // so the -5 arc between them is usable only once: the best elementary
+    // route is 0-1-2-0 (or its mirror) at -4. With k=0 a label keeps no
+    // memory beyond the vertex it's standing on, so the search can bounce
+    // 1<->2 repeatedly within maxlen and harvest the -5 arc twice, reaching
+    // -9. ng-route relaxation is a relaxation of elementarity, so it must
+    // never do worse than the fully elementary search.
+    #[test]
+    fn ng_route_relaxation_can_improve_on_elementary_optimum() {
+        let d = instance(
+            3,
+            vec![
+                vec![0.0, 1.0, 1.0],
+                vec![0.0, 0.0, -5.0],
+                vec![0.0, -5.0, 0.0],
+            ],
+            vec![vec![0, 1, 1], vec![1, 0, 1], vec![1, 1, 0]],
+        );
+        let resources = BitmaskResources {
+            resourcecapacity: 0,
+        };
+        let elementary = solve(&d, 0, &resources, 4, SolveMode::LabelCorrecting, 2);
+        let relaxed = solve(&d, 0, &resources, 4, SolveMode::LabelCorrecting, 0);
+        assert_eq!(elementary, -4.0);
+        assert_eq!(relaxed, -9.0);
+        assert!(relaxed <= elementary);
+    }
+
+    // Two distinct negative-reduced-cost depot routes exist, on disjoint
+    // vertex sets so neither dominates the other out of `labels[0]`:
+    // 0-1-0 at -4 and 0-2-3-0 at -3. Every other arc is priced at 100 so no
+    // other depot route is negative. `solve_columns` must reconstruct both
+    // vertex sequences, sorted cheapest first, and respect `max_routes`.
+    #[test]
+    fn solve_columns_reconstructs_routes_sorted_by_cost() {
+        let d = instance(
+            4,
+            vec![
+                vec![100.0, -4.0, -1.0, 100.0],
+                vec![0.0, 100.0, 100.0, 100.0],
+                vec![100.0, 100.0, 100.0, -1.0],
+                vec![-1.0, 100.0, 100.0, 100.0],
+            ],
+            vec![
+                vec![0, 1, 1, 1],
+                vec![1, 0, 1, 1],
+                vec![1, 1, 0, 1],
+                vec![1, 1, 1, 0],
+            ],
+        );
+        let resources = BitmaskResources {
+            resourcecapacity: 0,
+        };
+        let routes = solve_columns(&d, 0, &resources, 10, 3, 0.0, 10);
+        assert_eq!(routes.len(), 2);
+        assert_eq!(routes[0].cost, -4.0);
+        assert_eq!(routes[0].vertices, vec![0, 1, 0]);
+        assert_eq!(routes[1].cost, -3.0);
+        assert_eq!(routes[1].vertices, vec![0, 2, 3, 0]);
+
+        let truncated = solve_columns(&d, 0, &resources, 10, 3, 0.0, 1);
+        assert_eq!(truncated.len(), 1);
+        assert_eq!(truncated[0].cost, -4.0);
+    }
+
+    // A custom `ResourceExtension` (not `BitmaskResources`) that charges one
+    // unit of a single resource per arc, modeling a hop limit. Unconstrained,
+    // the cheapest route is 0-1-2-0 at -6; capping the hop count at 2 rules
+    // that 3-hop route out and the search falls back to the 2-hop 0-2-0 at
+    // -1, proving the extension is actually enforced rather than inert.
+    struct HopLimit {
+        max_hops: usize,
+    }
+
+    impl ResourceExtension for HopLimit {
+        fn consume(&self, _r: usize, _from: usize, _to: usize) -> usize {
+            1
+        }
+
+        fn capacity(&self, _r: usize) -> usize {
+            self.max_hops
+        }
+    }
+
+    #[test]
+    fn custom_resource_extension_is_enforced() {
+        let d = instance(
+            3,
+            vec![
+                vec![0.0, -1.0, -1.0],
+                vec![1.0, 0.0, -5.0],
+                vec![0.0, 5.0, 0.0],
+            ],
+            vec![vec![0, 1, 1], vec![1, 0, 1], vec![1, 1, 0]],
+        );
+        let unconstrained = BitmaskResources {
+            resourcecapacity: 0,
+        };
+        let unconstrained_cost = solve(&d, 0, &unconstrained, 10, SolveMode::LabelCorrecting, 2);
+        assert_eq!(unconstrained_cost, -6.0);
+
+        let capped = HopLimit { max_hops: 2 };
+        let capped_cost = solve(&d, 1, &capped, 10, SolveMode::LabelCorrecting, 2);
+        assert_eq!(capped_cost, -1.0);
+    }
 }